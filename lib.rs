@@ -33,10 +33,27 @@
 //!
 //! Refer to `slog-scope` crate documentation for more information.
 //!
+//! If you already have a root `Logger` and don't want to depend on
+//! `slog-scope` or set up a global scope guard, use
+//! [`init_with_logger`](fn.init_with_logger.html) instead, which logs
+//! directly to that `Logger`.
+//!
 //! ### Warning
 //!
-//! Be careful when using both methods at the same time, as a loop can be easily
-//! created: `log` -> `slog` -> `log` -> ...
+//! Using both methods at the same time can create a loop: `log` -> `slog` ->
+//! `log` -> ... A thread-local recursion guard breaks this cycle by
+//! dropping any `log` record produced while a `StdLog`/`StdLogJson`/
+//! `StdLogLocation` drain is still forwarding a previous record into `log`; see
+//! [`dropped_record_count`](fn.dropped_record_count.html) to check whether
+//! that guard is actually firing.
+//!
+//! ## Per-target level filtering
+//!
+//! `init` and `init_with_level` apply a single level to every `log` record.
+//! `init_with_env` (and `init_with_directives`) instead accept an
+//! `env_logger`-style directive string such as `debug,hyper=info` so
+//! individual targets can be filtered more or less aggressively than the
+//! rest of the application. See [`init_with_env`](fn.init_with_env.html).
 //!
 //! ## Compile-time log level filtering
 //!
@@ -54,12 +71,62 @@ extern crate log;
 extern crate slog_scope;
 
 use log::LogMetadata;
-use std::{fmt, io};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fmt, io};
 
 use slog::Level;
 use slog::KV;
 
-struct Logger;
+thread_local! {
+    // Set for the duration of a `StdLog`/`StdLogJson`/`StdLogLocation` call
+    // into `log::__log`,
+    // so a `Logger` invoked from within that call (i.e. `log` -> `slog` ->
+    // `log`) can recognize the loop and drop the record instead of
+    // forwarding it back into `slog`.
+    static IN_STDLOG: Cell<bool> = Cell::new(false);
+}
+
+static DROPPED_RECORDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of `log` records dropped so far by the `log -> slog -> log`
+/// recursion guard.
+///
+/// A non-zero (or climbing) count means `Logger` (via `init*`) and
+/// `StdLog`/`StdLogJson`/`StdLogLocation` are both installed in the same process in a way
+/// that would otherwise infinitely recurse; use this to notice that
+/// misconfiguration rather than a hang.
+pub fn dropped_record_count() -> usize {
+    DROPPED_RECORDS.load(Ordering::Relaxed)
+}
+
+/// RAII guard marking that a `slog` `Record` is currently being forwarded
+/// into the `log` crate, so a re-entrant call back into `Logger` can be
+/// detected and dropped.
+struct RecursionGuard;
+
+impl RecursionGuard {
+    fn enter() -> Self {
+        IN_STDLOG.with(|f| f.set(true));
+        RecursionGuard
+    }
+}
+
+impl Drop for RecursionGuard {
+    fn drop(&mut self) {
+        IN_STDLOG.with(|f| f.set(false));
+    }
+}
+
+/// A parsed `RUST_LOG`-style directive table.
+///
+/// Entries are `(target-prefix, max-level)` pairs sorted so the longest
+/// (most specific) prefix is tried first; the final entry is always the
+/// catch-all `None` prefix holding the default level for targets that don't
+/// match anything more specific.
+struct Logger {
+    directives: Vec<(Option<String>, Level)>,
+}
 
 fn log_to_slog_level(level: log::LogLevel) -> Level {
     match level {
@@ -71,32 +138,164 @@ fn log_to_slog_level(level: log::LogLevel) -> Level {
     }
 }
 
+/// Order levels from least to most verbose so directive tables can be
+/// compared without relying on `slog::Level`'s own trait impls.
+fn level_rank(level: Level) -> u8 {
+    match level {
+        Level::Critical => 0,
+        Level::Error => 1,
+        Level::Warning => 2,
+        Level::Info => 3,
+        Level::Debug => 4,
+        Level::Trace => 5,
+    }
+}
+
+fn slog_to_log_level_filter(level: Level) -> log::LogLevelFilter {
+    match level {
+        Level::Critical | Level::Error => log::LogLevelFilter::Error,
+        Level::Warning => log::LogLevelFilter::Warn,
+        Level::Info => log::LogLevelFilter::Info,
+        Level::Debug => log::LogLevelFilter::Debug,
+        Level::Trace => log::LogLevelFilter::Trace,
+    }
+}
+
+fn parse_level(s: &str) -> Option<Level> {
+    match s.to_lowercase().as_str() {
+        "critical" => Some(Level::Critical),
+        "error" => Some(Level::Error),
+        "warn" | "warning" => Some(Level::Warning),
+        "info" => Some(Level::Info),
+        "debug" => Some(Level::Debug),
+        "trace" => Some(Level::Trace),
+        _ => None,
+    }
+}
+
+/// Parse a `RUST_LOG`-style directive string into a longest-prefix-first
+/// table, terminated by a catch-all `None` entry holding the default level.
+///
+/// Each comma-separated directive is one of:
+///
+/// * `level` - sets the default level for targets matched by nothing else
+/// * `path` - enables `path` (and everything nested under it) at `Trace`
+/// * `path=level` - caps `path` (and everything nested under it) at `level`
+fn parse_directives(spec: &str) -> Vec<(Option<String>, Level)> {
+    let mut named = Vec::new();
+    let mut default = Level::Error;
+
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        if let Some(eq) = part.find('=') {
+            let (path, level_str) = part.split_at(eq);
+            if let Some(level) = parse_level(&level_str[1..]) {
+                named.push((path.to_string(), level));
+            }
+        } else if let Some(level) = parse_level(part) {
+            default = level;
+        } else {
+            named.push((part.to_string(), Level::Trace));
+        }
+    }
+
+    named.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+
+    let mut directives: Vec<(Option<String>, Level)> = named
+        .into_iter()
+        .map(|(path, level)| (Some(path), level))
+        .collect();
+    directives.push((None, default));
+    directives
+}
+
+fn level_for(directives: &[(Option<String>, Level)], target: &str) -> Level {
+    for &(ref prefix, level) in directives {
+        match *prefix {
+            Some(ref p) if target.starts_with(p.as_str()) => return level,
+            None => return level,
+            _ => {}
+        }
+    }
+    // Every directive table ends with a catch-all `None` entry, so this
+    // is unreachable in practice.
+    Level::Error
+}
+
+/// Build the `slog::Record` a `log::LogRecord` corresponds to and hand it
+/// to `dispatch`, which decides where the record actually goes (a global
+/// `slog-scope` logger for `Logger`, an owned one for `FixedLogger`).
+fn dispatch_log_record<F: FnOnce(&slog::Record)>(r: &log::LogRecord, level: Level, dispatch: F) {
+    let args = r.args();
+    let target = r.target();
+    let module = r.location().__module_path;
+    let file = r.location().__file;
+    let line = r.location().line();
+
+    let s = slog::RecordStatic {
+        location: &slog::RecordLocation {
+            file: file,
+            line: line,
+            column: 0,
+            function: "",
+            module: module,
+        },
+        level: level,
+        tag: target,
+    };
+    dispatch(&slog::Record::new(&s, args, b!()))
+}
+
 impl log::Log for Logger {
-    fn enabled(&self, _: &LogMetadata) -> bool {
-        true
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        let level = log_to_slog_level(metadata.level());
+        level_rank(level) <= level_rank(level_for(&self.directives, metadata.target()))
+    }
+
+    fn log(&self, r: &log::LogRecord) {
+        if IN_STDLOG.with(Cell::get) {
+            DROPPED_RECORDS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let level = log_to_slog_level(r.metadata().level());
+
+        if level_rank(level) > level_rank(level_for(&self.directives, r.target())) {
+            return;
+        }
+
+        dispatch_log_record(r, level, |record| {
+            slog_scope::with_logger(|logger| logger.log(record))
+        })
+    }
+}
+
+/// Backend for [`init_with_logger`](fn.init_with_logger.html): forwards
+/// `log` records straight to an owned `slog::Logger` instead of going
+/// through `slog_scope`.
+struct FixedLogger {
+    logger: slog::Logger,
+    directives: Vec<(Option<String>, Level)>,
+}
+
+impl log::Log for FixedLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        let level = log_to_slog_level(metadata.level());
+        level_rank(level) <= level_rank(level_for(&self.directives, metadata.target()))
     }
 
     fn log(&self, r: &log::LogRecord) {
+        if IN_STDLOG.with(Cell::get) {
+            DROPPED_RECORDS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
         let level = log_to_slog_level(r.metadata().level());
 
-        let args = r.args();
-        let target = r.target();
-        let module = r.location().__module_path;
-        let file = r.location().__file;
-        let line = r.location().line();
+        if level_rank(level) > level_rank(level_for(&self.directives, r.target())) {
+            return;
+        }
 
-        let s = slog::RecordStatic {
-            location: &slog::RecordLocation {
-                file: file,
-                line: line,
-                column: 0,
-                function: "",
-                module: module,
-            },
-            level: level,
-            tag: target,
-        };
-        slog_scope::with_logger(|logger| logger.log(&slog::Record::new(&s, args, b!())))
+        dispatch_log_record(r, level, |record| self.logger.log(record))
     }
 }
 
@@ -167,7 +366,115 @@ pub fn init() -> Result<(), log::SetLoggerError> {
 pub fn init_with_level(level: log::LogLevel) -> Result<(), log::SetLoggerError> {
     log::set_logger(|max_log_level| {
         max_log_level.set(level.to_log_level_filter());
-        Box::new(Logger)
+        Box::new(Logger {
+            directives: vec![(None, log_to_slog_level(level))],
+        })
+    })
+}
+
+/// Register `slog-stdlog` as `log` backend, filtering per-target using the
+/// same directive syntax as `env_logger`'s `RUST_LOG`.
+///
+/// The directive string is read from the `RUST_LOG` environment variable,
+/// e.g. `RUST_LOG=debug,hyper=info` enables `Debug` everywhere except the
+/// `hyper` target tree, which is capped at `Info`. Note this only matches
+/// `env_logger`'s directive *syntax*, not its default: unlike `env_logger`,
+/// which logs nothing when `RUST_LOG` is unset, an unset or empty
+/// `RUST_LOG` here falls back to `Level::Error` for every target. See
+/// [`init_with_directives`](fn.init_with_directives.html) to supply the
+/// directive string explicitly instead of reading it from the environment.
+///
+/// ```
+/// #[macro_use]
+/// extern crate log;
+/// #[macro_use(slog_o, slog_kv)]
+/// extern crate slog;
+/// extern crate slog_stdlog;
+/// extern crate slog_scope;
+/// extern crate slog_term;
+/// extern crate slog_async;
+///
+/// use slog::Drain;
+///
+/// fn main() {
+///     let decorator = slog_term::TermDecorator::new().build();
+///     let drain = slog_term::FullFormat::new(decorator).build().fuse();
+///     let drain = slog_async::Async::new(drain).build().fuse();
+///     let logger = slog::Logger::root(drain, slog_o!("version" => env!("CARGO_PKG_VERSION")));
+///
+///     let _scope_guard = slog_scope::set_global_logger(logger);
+///     let _log_guard = slog_stdlog::init_with_env().unwrap();
+///     // Note: this `info!(...)` macro comes from `log` crate
+///     info!("standard logging redirected to slog");
+/// }
+/// ```
+pub fn init_with_env() -> Result<(), log::SetLoggerError> {
+    init_with_directives(&env::var("RUST_LOG").unwrap_or_default())
+}
+
+/// Register `slog-stdlog` as `log` backend, filtering per-target according
+/// to an explicit `RUST_LOG`-style directive string.
+///
+/// See [`init_with_env`](fn.init_with_env.html) for the directive syntax and
+/// an equivalent example that reads the directives from the environment
+/// instead.
+pub fn init_with_directives(spec: &str) -> Result<(), log::SetLoggerError> {
+    let directives = parse_directives(spec);
+    let max_level = directives
+        .iter()
+        .fold(Level::Critical, |acc, &(_, level)| {
+            if level_rank(level) > level_rank(acc) {
+                level
+            } else {
+                acc
+            }
+        });
+
+    log::set_logger(move |max_log_level| {
+        max_log_level.set(slog_to_log_level_filter(max_level));
+        Box::new(Logger { directives: directives })
+    })
+}
+
+/// Register `slog-stdlog` as `log` backend, forwarding directly to `logger`.
+///
+/// Unlike `init`/`init_with_level`/`init_with_env`, this does not route
+/// through `slog_scope::with_logger` at all (and doesn't depend on
+/// `slog-scope`), since `logger` is stored in the backend directly. That
+/// makes it usable in contexts - library initialization, tests, embedded
+/// applications - where installing a global scope guard is undesirable.
+/// Level filtering is left to `logger`'s own `Drain`, so every record is
+/// passed through to it.
+///
+/// ```
+/// #[macro_use]
+/// extern crate log;
+/// #[macro_use(slog_o, slog_kv)]
+/// extern crate slog;
+/// extern crate slog_stdlog;
+/// extern crate slog_term;
+/// extern crate slog_async;
+///
+/// use slog::Drain;
+///
+/// fn main() {
+///     let decorator = slog_term::TermDecorator::new().build();
+///     let drain = slog_term::FullFormat::new(decorator).build().fuse();
+///     let drain = slog_async::Async::new(drain).build().fuse();
+///     let logger = slog::Logger::root(drain, slog_o!("version" => env!("CARGO_PKG_VERSION")));
+///
+///     let _log_guard = slog_stdlog::init_with_logger(logger).unwrap();
+///     // Note: this `info!(...)` macro comes from `log` crate
+///     info!("standard logging redirected to slog, no slog-scope required");
+/// }
+/// ```
+pub fn init_with_logger(logger: slog::Logger) -> Result<(), log::SetLoggerError> {
+    log::set_logger(|max_log_level| {
+        max_log_level.set(log::LogLevelFilter::Trace);
+        Box::new(FixedLogger {
+            logger: logger,
+            directives: vec![(None, Level::Trace)],
+        })
     })
 }
 
@@ -178,12 +485,99 @@ pub fn init_with_level(level: log::LogLevel) -> Result<(), log::SetLoggerError>
 /// in the first place. The message and key-value pairs will be formated
 /// to be one string.
 ///
-/// Caution needs to be taken to prevent circular loop where `Logger`
-/// installed via `slog-stdlog::set_logger` would log things to a `StdLog`
-/// drain, which would again log things to the global `Logger` and so on
-/// leading to an infinite recursion.
+/// A `Logger` installed via `init`/`init_with_level`/etc. would otherwise
+/// log things right back to this drain, which would again log things to
+/// the global `Logger` and so on leading to an infinite recursion; a
+/// thread-local recursion guard breaks that cycle by dropping the re-entrant
+/// `log` record (see [`dropped_record_count`](fn.dropped_record_count.html)).
 pub struct StdLog;
 
+impl StdLog {
+    /// Build a `StdLog`-like drain that renders each `Record` as a single
+    /// line JSON object (`{"msg": ..., "key": value, ...}`) instead of the
+    /// `, key: value` text `StdLog` produces.
+    ///
+    /// This keeps the structured key-value data machine-readable once it
+    /// reaches a `log` backend such as a file or syslog appender.
+    ///
+    /// `"msg"` is reserved for the record's rendered message: a kv pair
+    /// literally named `msg` is emitted as `"_msg"` instead of silently
+    /// overwriting it.
+    pub fn json() -> StdLogJson {
+        StdLogJson
+    }
+
+    /// Build a `StdLog`-like drain that preserves the `column` and
+    /// `function` fields of the slog `Record`'s location, which `log`'s 0.x
+    /// `LogRecord` has no room for, and keeps the slog `tag` separate from
+    /// the module-derived `log` target rather than letting the tag
+    /// overwrite it.
+    ///
+    /// The target passed to `log` is always `info.module()`; the call site
+    /// (`module::function:line:col`, with the `::function` segment omitted
+    /// when slog didn't record one) and, if set, the tag are rendered into
+    /// a prefix ahead of the message instead, so nothing slog tracked about
+    /// the record is silently dropped on the hop into `log`.
+    pub fn with_location() -> StdLogLocation {
+        StdLogLocation
+    }
+}
+
+/// Drain logging `Record`s into `log` crate as single-line JSON objects.
+///
+/// See [`StdLog::json`](struct.StdLog.html#method.json) for how to
+/// construct one; behaves exactly like `StdLog` otherwise, including the
+/// same recursion caveat.
+pub struct StdLogJson;
+
+/// Drain logging `Record`s into `log` crate with the full call site and tag
+/// preserved in the message.
+///
+/// See [`StdLog::with_location`](struct.StdLog.html#method.with_location)
+/// for how to construct one; behaves exactly like `StdLog` otherwise,
+/// including the same recursion caveat.
+pub struct StdLogLocation;
+
+/// Forward a rendered slog `Record` into the `log` crate under `target`,
+/// preserving its level and file/line source location.
+fn forward_to_log(info: &slog::Record, target: &str, payload: fmt::Arguments) {
+    let level = match info.level() {
+        slog::Level::Critical | slog::Level::Error => log::LogLevel::Error,
+        slog::Level::Warning => log::LogLevel::Warn,
+        slog::Level::Info => log::LogLevel::Info,
+        slog::Level::Debug => log::LogLevel::Debug,
+        slog::Level::Trace => log::LogLevel::Trace,
+    };
+
+    let location = log::LogLocation {
+        __module_path: info.module(),
+        __file: info.file(),
+        __line: info.line(),
+    };
+
+    let _guard = RecursionGuard::enter();
+    // Please don't yell at me for this! :D
+    // https://github.com/rust-lang-nursery/log/issues/95
+    log::__log(level, target, &location, payload);
+}
+
+/// Fall back from `tag` to `fallback` when `tag` is unset (slog represents
+/// "no tag" as an empty string, not `None`).
+fn pick_target<'a>(tag: &'a str, fallback: &'a str) -> &'a str {
+    if tag.is_empty() {
+        fallback
+    } else {
+        tag
+    }
+}
+
+/// The `StdLog`/`StdLogJson` target: the slog `tag` if set, falling back to
+/// the module path otherwise. This conflates tag and target, which is
+/// exactly what `StdLogLocation` avoids.
+fn tag_or_module_target<'a>(info: &'a slog::Record) -> &'a str {
+    pick_target(info.tag(), info.module())
+}
+
 struct LazyLogString<'a> {
     info: &'a slog::Record<'a>,
     logger_values: &'a slog::OwnedKVList,
@@ -225,31 +619,90 @@ impl slog::Drain for StdLog {
     type Err = io::Error;
     type Ok = ();
     fn log(&self, info: &slog::Record, logger_values: &slog::OwnedKVList) -> io::Result<()> {
-        let level = match info.level() {
-            slog::Level::Critical | slog::Level::Error => log::LogLevel::Error,
-            slog::Level::Warning => log::LogLevel::Warn,
-            slog::Level::Info => log::LogLevel::Info,
-            slog::Level::Debug => log::LogLevel::Debug,
-            slog::Level::Trace => log::LogLevel::Trace,
-        };
-
-        let mut target = info.tag();
-        if target.is_empty() {
-            target = info.module();
+        let lazy = LazyLogString::new(info, logger_values);
+        forward_to_log(info, tag_or_module_target(info), format_args!("{}", lazy));
+        Ok(())
+    }
+}
+
+impl slog::Drain for StdLogJson {
+    type Err = io::Error;
+    type Ok = ();
+    fn log(&self, info: &slog::Record, logger_values: &slog::OwnedKVList) -> io::Result<()> {
+        let lazy = JsonLogString::new(info, logger_values);
+        forward_to_log(info, tag_or_module_target(info), format_args!("{}", lazy));
+        Ok(())
+    }
+}
+
+impl slog::Drain for StdLogLocation {
+    type Err = io::Error;
+    type Ok = ();
+    fn log(&self, info: &slog::Record, logger_values: &slog::OwnedKVList) -> io::Result<()> {
+        let lazy = LocationLogString::new(info, logger_values);
+        forward_to_log(info, info.module(), format_args!("{}", lazy));
+        Ok(())
+    }
+}
+
+struct LocationLogString<'a> {
+    info: &'a slog::Record<'a>,
+    logger_values: &'a slog::OwnedKVList,
+}
+
+impl<'a> LocationLogString<'a> {
+    fn new(info: &'a slog::Record, logger_values: &'a slog::OwnedKVList) -> Self {
+        LocationLogString {
+            info: info,
+            logger_values: logger_values,
         }
+    }
+}
+
+/// Render the `module::function:line:col [tag]` prefix `LocationLogString`
+/// puts ahead of the message, omitting the `::function` segment when slog
+/// didn't record one and the `[tag]` suffix when no tag is set.
+fn format_location_prefix(module: &str, function: &str, line: u32, column: u32, tag: &str) -> String {
+    let mut prefix = String::new();
+    prefix.push_str(module);
+    if !function.is_empty() {
+        prefix.push_str("::");
+        prefix.push_str(function);
+    }
+    prefix.push_str(&format!(":{}:{}", line, column));
+    if !tag.is_empty() {
+        prefix.push_str(&format!(" [{}]", tag));
+    }
+    prefix
+}
 
-        let location = log::LogLocation {
-            __module_path: info.module(),
-            __file: info.file(),
-            __line: info.line(),
-        };
+impl<'a> fmt::Display for LocationLogString<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let loc = self.info.location();
+        try!(write!(
+            f,
+            "{}",
+            format_location_prefix(loc.module, loc.function, loc.line, loc.column, self.info.tag())
+        ));
 
-        let lazy = LazyLogString::new(info, logger_values);
-        // Please don't yell at me for this! :D
-        // https://github.com/rust-lang-nursery/log/issues/95
-        log::__log(level, target, &location, format_args!("{}", lazy));
+        try!(write!(f, " {}", self.info.msg()));
 
-        Ok(())
+        let io = io::Cursor::new(Vec::new());
+        let mut ser = KSV::new(io);
+
+        let res = {
+            || -> io::Result<()> {
+                try!(self.logger_values.serialize(self.info, &mut ser));
+                try!(self.info.kv().serialize(self.info, &mut ser));
+                Ok(())
+            }
+        }().map_err(|_| fmt::Error);
+
+        try!(res);
+
+        let values = ser.into_inner().into_inner();
+
+        write!(f, "{}", String::from_utf8_lossy(&values))
     }
 }
 
@@ -268,9 +721,326 @@ impl<W: io::Write> KSV<W> {
     }
 }
 
+struct JsonLogString<'a> {
+    info: &'a slog::Record<'a>,
+    logger_values: &'a slog::OwnedKVList,
+}
+
+impl<'a> JsonLogString<'a> {
+    fn new(info: &'a slog::Record, logger_values: &'a slog::OwnedKVList) -> Self {
+        JsonLogString {
+            info: info,
+            logger_values: logger_values,
+        }
+    }
+}
+
+impl<'a> fmt::Display for JsonLogString<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{{\"msg\":{}", json_quote(&self.info.msg().to_string())));
+
+        let io = io::Cursor::new(Vec::new());
+        let mut ser = JsonKV::new(io);
+
+        let res = {
+            || -> io::Result<()> {
+                try!(self.logger_values.serialize(self.info, &mut ser));
+                try!(self.info.kv().serialize(self.info, &mut ser));
+                Ok(())
+            }
+        }().map_err(|_| fmt::Error);
+
+        try!(res);
+
+        let values = ser.into_inner().into_inner();
+
+        try!(write!(f, "{}", String::from_utf8_lossy(&values)));
+        write!(f, "}}")
+    }
+}
+
+/// Escape and quote a string as a JSON string literal.
+fn json_quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// JSON object-field serializer used by `StdLog::json()`.
+///
+/// Unlike `KSV`, numeric and boolean values are emitted as raw JSON tokens
+/// and strings are escaped and quoted, so the resulting line is a valid
+/// JSON document rather than ad-hoc text.
+struct JsonKV<W: io::Write> {
+    io: W,
+}
+
+impl<W: io::Write> JsonKV<W> {
+    fn new(io: W) -> Self {
+        JsonKV { io: io }
+    }
+
+    fn into_inner(self) -> W {
+        self.io
+    }
+
+    /// `"msg"` is reserved for the record's rendered message (written ahead
+    /// of any kv pairs in `JsonLogString::fmt`); rename a same-named kv pair
+    /// instead of letting it silently clobber the message when the line is
+    /// parsed back.
+    fn quoted_key(&self, key: slog::Key) -> String {
+        if key == "msg" {
+            json_quote("_msg")
+        } else {
+            json_quote(key)
+        }
+    }
+
+    fn emit_raw(&mut self, key: slog::Key, val: &fmt::Display) -> slog::Result {
+        try!(write!(self.io, ",{}:{}", self.quoted_key(key), val));
+        Ok(())
+    }
+
+    /// `NaN`/`inf`/`-inf` have no representation in the JSON number grammar,
+    /// so emit those as a quoted string (e.g. `"NaN"`) instead of raw text
+    /// that would make the line invalid JSON.
+    fn emit_finite(&mut self, key: slog::Key, val: f64) -> slog::Result {
+        if val.is_finite() {
+            self.emit_raw(key, &val)
+        } else {
+            try!(write!(
+                self.io,
+                ",{}:{}",
+                self.quoted_key(key),
+                json_quote(&val.to_string())
+            ));
+            Ok(())
+        }
+    }
+}
+
+impl<W: io::Write> slog::Serializer for JsonKV<W> {
+    fn emit_bool(&mut self, key: slog::Key, val: bool) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_u8(&mut self, key: slog::Key, val: u8) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_i8(&mut self, key: slog::Key, val: i8) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_u16(&mut self, key: slog::Key, val: u16) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_i16(&mut self, key: slog::Key, val: i16) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_u32(&mut self, key: slog::Key, val: u32) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_i32(&mut self, key: slog::Key, val: i32) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_u64(&mut self, key: slog::Key, val: u64) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_i64(&mut self, key: slog::Key, val: i64) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_usize(&mut self, key: slog::Key, val: usize) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_isize(&mut self, key: slog::Key, val: isize) -> slog::Result {
+        self.emit_raw(key, &val)
+    }
+
+    fn emit_f32(&mut self, key: slog::Key, val: f32) -> slog::Result {
+        self.emit_finite(key, val as f64)
+    }
+
+    fn emit_f64(&mut self, key: slog::Key, val: f64) -> slog::Result {
+        self.emit_finite(key, val)
+    }
+
+    fn emit_str(&mut self, key: slog::Key, val: &str) -> slog::Result {
+        try!(write!(self.io, ",{}:{}", self.quoted_key(key), json_quote(val)));
+        Ok(())
+    }
+
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        try!(write!(
+            self.io,
+            ",{}:{}",
+            self.quoted_key(key),
+            json_quote(&val.to_string())
+        ));
+        Ok(())
+    }
+}
+
 impl<W: io::Write> slog::Serializer for KSV<W> {
     fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
         try!(write!(self.io, ", {}: {}", key, val));
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::Serializer;
+
+    #[test]
+    fn recursion_guard_sets_flag_on_enter_and_clears_it_on_drop() {
+        assert!(!IN_STDLOG.with(Cell::get));
+        {
+            let _guard = RecursionGuard::enter();
+            assert!(IN_STDLOG.with(Cell::get));
+        }
+        assert!(!IN_STDLOG.with(Cell::get));
+    }
+
+    #[test]
+    fn parse_directives_defaults_to_error_when_empty() {
+        let directives = parse_directives("");
+        assert_eq!(directives, vec![(None, Level::Error)]);
+    }
+
+    #[test]
+    fn parse_directives_sets_default_level() {
+        let directives = parse_directives("debug");
+        assert_eq!(level_for(&directives, "anything"), Level::Debug);
+    }
+
+    #[test]
+    fn parse_directives_longest_prefix_wins() {
+        let directives = parse_directives("debug,hyper=info,hyper::tls=warn");
+        assert_eq!(
+            level_for(&directives, "hyper::tls::handshake"),
+            Level::Warning
+        );
+        assert_eq!(level_for(&directives, "hyper::client"), Level::Info);
+        assert_eq!(level_for(&directives, "myapp"), Level::Debug);
+    }
+
+    #[test]
+    fn parse_directives_bare_path_implies_trace() {
+        let directives = parse_directives("hyper");
+        assert_eq!(level_for(&directives, "hyper::client"), Level::Trace);
+    }
+
+    #[test]
+    fn parse_directives_keeps_first_of_duplicate_paths() {
+        // Both `hyper=info` and `hyper=warn` have the same prefix length, so
+        // the sort that orders directives longest-prefix-first is stable and
+        // the first one written wins.
+        let directives = parse_directives("hyper=info,hyper=warn");
+        assert_eq!(level_for(&directives, "hyper::client"), Level::Info);
+    }
+
+    #[test]
+    fn parse_directives_drops_malformed_directive() {
+        // `path=` with no level after the `=` doesn't parse as a level, so
+        // the whole directive is dropped rather than matching everything.
+        let directives = parse_directives("path=,debug");
+        assert_eq!(level_for(&directives, "path"), Level::Debug);
+    }
+
+    #[test]
+    fn json_quote_escapes_special_characters() {
+        assert_eq!(json_quote("plain"), "\"plain\"");
+        assert_eq!(json_quote("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_quote("line\nbreak\ttab"), "\"line\\nbreak\\ttab\"");
+        assert_eq!(json_quote("\u{1}"), "\"\\u0001\"");
+    }
+
+    #[test]
+    fn json_kv_renders_numbers_and_strings() {
+        let mut ser = JsonKV::new(io::Cursor::new(Vec::new()));
+        ser.emit_u64("count", 3).unwrap();
+        ser.emit_bool("ok", true).unwrap();
+        ser.emit_str("name", "a\"b").unwrap();
+        let out = String::from_utf8(ser.into_inner().into_inner()).unwrap();
+        assert_eq!(out, ",\"count\":3,\"ok\":true,\"name\":\"a\\\"b\"");
+    }
+
+    #[test]
+    fn json_kv_quotes_non_finite_floats() {
+        let mut ser = JsonKV::new(io::Cursor::new(Vec::new()));
+        ser.emit_f64("value", ::std::f64::NAN).unwrap();
+        let out = String::from_utf8(ser.into_inner().into_inner()).unwrap();
+        assert_eq!(out, ",\"value\":\"NaN\"");
+    }
+
+    #[test]
+    fn json_kv_renames_reserved_msg_key() {
+        let mut ser = JsonKV::new(io::Cursor::new(Vec::new()));
+        ser.emit_str("msg", "oops").unwrap();
+        let out = String::from_utf8(ser.into_inner().into_inner()).unwrap();
+        assert_eq!(out, ",\"_msg\":\"oops\"");
+    }
+
+    #[test]
+    fn format_location_prefix_omits_function_segment_when_empty() {
+        assert_eq!(
+            format_location_prefix("mymodule", "", 42, 0, ""),
+            "mymodule:42:0"
+        );
+    }
+
+    #[test]
+    fn format_location_prefix_includes_function_segment_when_set() {
+        assert_eq!(
+            format_location_prefix("mymodule", "my_fn", 42, 7, ""),
+            "mymodule::my_fn:42:7"
+        );
+    }
+
+    #[test]
+    fn format_location_prefix_omits_tag_suffix_when_empty() {
+        assert_eq!(
+            format_location_prefix("mymodule", "my_fn", 1, 2, ""),
+            "mymodule::my_fn:1:2"
+        );
+    }
+
+    #[test]
+    fn format_location_prefix_includes_tag_suffix_when_set() {
+        assert_eq!(
+            format_location_prefix("mymodule", "my_fn", 1, 2, "mytag"),
+            "mymodule::my_fn:1:2 [mytag]"
+        );
+    }
+
+    #[test]
+    fn pick_target_falls_back_when_tag_is_empty() {
+        assert_eq!(pick_target("", "mymodule"), "mymodule");
+    }
+
+    #[test]
+    fn pick_target_prefers_tag_when_set() {
+        assert_eq!(pick_target("mytag", "mymodule"), "mytag");
+    }
+}